@@ -1,18 +1,37 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use super::{configuration, Error};
+use chrono::{DateTime, Utc};
 use futures;
+use futures::future::Loop;
 use futures::{Future, Stream};
 use hyper;
+use hyper::header::ACCEPT;
+use hyper::header::CONTENT_TYPE;
 use hyper::header::USER_AGENT;
 use hyper::header::AUTHORIZATION;
+use hyper::header::LOCATION;
+use hyper::header::RETRY_AFTER;
+use hyper::header::WWW_AUTHENTICATE;
 use base64::encode;
+use rand::Rng;
 use serde;
+use serde::de::Error as DeError;
+use serde::de::IntoDeserializer;
+use serde_derive::Deserialize;
 use serde_json;
+use tokio_timer::Delay;
 
 const MIME_APPLICATION_WWW_FORM_URLENCODED: &'static str = "application/x-www-form-urlencoded";
 const MIME_APPLICATION_JSON: &'static str = "application/json";
+const MIME_TEXT_PLAIN: &'static str = "text/plain";
+const MIME_APPLICATION_OCTET_STREAM: &'static str = "application/octet-stream";
+
+// Subtracted from a cached token's reported lifetime so that it gets refreshed a little before
+// a server would actually reject it.
+const OAUTH_TOKEN_EXPIRY_SKEW_SECS: i64 = 30;
 
 pub(crate) struct ApiKey {
     pub in_header: bool,
@@ -36,14 +55,554 @@ pub(crate) enum Auth {
     Oauth,
 }
 
+// A bearer token obtained from an OAuth2/registry-style token endpoint (see fetch_oauth_token),
+// cached on Configuration and reused across requests until it is close to expiring.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenAuth {
+    pub token: String,
+    pub expires_in: i64,
+    pub issued_at: DateTime<Utc>,
+    pub refresh_token: Option<String>,
+    // The challenge this token was fetched for, kept around so a near-expiry token can be
+    // refreshed proactively without waiting for another 401.
+    #[serde(skip)]
+    challenge: Option<BearerChallenge>,
+}
+
+impl TokenAuth {
+    fn is_expired(&self) -> bool {
+        let expiry = self.issued_at + chrono::Duration::seconds(self.expires_in)
+            - chrono::Duration::seconds(OAUTH_TOKEN_EXPIRY_SKEW_SECS);
+        Utc::now() >= expiry
+    }
+}
+
+// The directives parsed out of a WWW-Authenticate: Bearer realm="...",service="...",scope="..."
+// challenge header.
+#[derive(Debug, Clone)]
+struct BearerChallenge {
+    realm: String,
+    service: Option<String>,
+    scope: Option<String>,
+}
+
+fn parse_bearer_challenge(header_value: &str) -> Option<BearerChallenge> {
+    let rest = header_value.trim();
+    if !rest.starts_with("Bearer ") {
+        return None;
+    }
+
+    let mut realm = None;
+    let mut service = None;
+    let mut scope = None;
+    for part in split_directives(&rest[7..]) {
+        let mut kv = part.splitn(2, '=');
+        let key = kv.next()?.trim();
+        let value = kv.next()?.trim().trim_matches('"');
+        match key {
+            "realm" => realm = Some(value.to_owned()),
+            "service" => service = Some(value.to_owned()),
+            "scope" => scope = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+
+    realm.map(|realm| BearerChallenge { realm, service, scope })
+}
+
+// Splits a comma-separated directive list on top-level commas only, so a quoted value like
+// scope="repository:foo:pull,push" isn't cut in half.
+fn split_directives(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// Exchanges a parsed WWW-Authenticate bearer challenge for a token, per the registry-style token
+// auth flow: a GET to challenge.realm with service/scope as query params, optionally under
+// conf.basic_auth. When refresh_token is set, it's sent along with grant_type=refresh_token so a
+// near-expiry token can be renewed without a fresh 401 challenge.
+fn fetch_oauth_token<'a, C>(
+    conf: &'a configuration::Configuration<C>,
+    challenge: &BearerChallenge,
+    refresh_token: Option<&str>,
+) -> Box<Future<Item = TokenAuth, Error = Error<serde_json::Value>> + 'a + Send>
+where
+    C: hyper::client::connect::Connect + 'static,
+{
+    let mut token_url = ::url::form_urlencoded::Serializer::new(format!("{}?", challenge.realm));
+    if let Some(ref service) = challenge.service {
+        token_url.append_pair("service", service);
+    }
+    if let Some(ref scope) = challenge.scope {
+        token_url.append_pair("scope", scope);
+    }
+    if let Some(refresh_token) = refresh_token {
+        token_url.append_pair("grant_type", "refresh_token");
+        token_url.append_pair("refresh_token", refresh_token);
+    }
+
+    let uri: hyper::Uri = match token_url.finish().parse() {
+        Ok(u) => u,
+        Err(e) => return Box::new(futures::future::err(Error::UriError(e))),
+    };
+
+    let mut builder = hyper::Request::builder();
+    let req_builder = builder.uri(uri).method(hyper::Method::GET);
+
+    if let Some(ref auth_conf) = conf.basic_auth {
+        let ref username = auth_conf.0;
+        let user_password = if let Some(ref password) = auth_conf.1 {
+            format!("{}:{}", username, password)
+        } else {
+            username.to_owned()
+        };
+        req_builder.header(AUTHORIZATION, format!("Basic {}", encode(&user_password)));
+    }
+
+    let req = req_builder.body(hyper::Body::default()).unwrap();
+
+    Box::new(
+        conf.client
+            .request(req)
+            .map_err(Error::from)
+            .and_then(|resp| {
+                let (head, body) = resp.into_parts();
+                body.concat2()
+                    .map_err(Error::from)
+                    .and_then(move |body| {
+                        if head.status.is_success() {
+                            Ok(body)
+                        } else {
+                            Err(Error::from((head.status, &*body)))
+                        }
+                    })
+            })
+            .and_then({
+                let challenge = challenge.clone();
+                move |body| {
+                    serde_json::from_slice::<TokenAuth>(&body)
+                        .map(|mut token| {
+                            token.challenge = Some(challenge);
+                            token
+                        })
+                        .map_err(Error::from)
+                }
+            }),
+    )
+}
+
+#[derive(Clone)]
+enum RequestBody {
+    Form(String),
+    Json(String),
+    Multipart { boundary: String, bytes: Vec<u8> },
+    Empty,
+}
+
+// A file to be uploaded as one part of a multipart/form-data body, added via with_file_param.
+#[derive(Clone)]
+pub(crate) struct FilePart {
+    filename: String,
+    content_type: String,
+    bytes: Vec<u8>,
+}
+
+fn random_multipart_boundary() -> String {
+    let mut rng = rand::thread_rng();
+    (0..32).map(|_| ::std::char::from_digit(rng.gen_range(0, 16), 16).unwrap()).collect()
+}
+
+// Assembles a multipart/form-data body: each of form_params as a text part, followed by each of
+// multipart_params as a file part, terminated by the closing boundary.
+fn build_multipart_body(
+    form_params: &HashMap<String, String>,
+    multipart_params: &HashMap<String, FilePart>,
+) -> RequestBody {
+    let boundary = random_multipart_boundary();
+    let mut bytes = Vec::new();
+
+    for (name, value) in form_params {
+        bytes.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        bytes.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name).as_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.extend_from_slice(b"\r\n");
+    }
+
+    for (name, file) in multipart_params {
+        bytes.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        bytes.extend_from_slice(
+            format!(
+                "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                name, file.filename, file.content_type,
+            ).as_bytes(),
+        );
+        bytes.extend_from_slice(&file.bytes);
+        bytes.extend_from_slice(b"\r\n");
+    }
+
+    bytes.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    RequestBody::Multipart { boundary, bytes }
+}
+
+// Retry/backoff and redirect-following behavior for execute(), configured once on Configuration
+// and shared by every request made through it. Not Clone: Configuration (which holds this) isn't
+// Clone either, since its oauth_token field is a Mutex.
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: Duration,
+    pub retryable_status_codes: Vec<hyper::StatusCode>,
+    pub max_redirects: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+            jitter: Duration::from_millis(100),
+            retryable_status_codes: vec![
+                hyper::StatusCode::TOO_MANY_REQUESTS,
+                hyper::StatusCode::BAD_GATEWAY,
+                hyper::StatusCode::SERVICE_UNAVAILABLE,
+                hyper::StatusCode::GATEWAY_TIMEOUT,
+            ],
+            max_redirects: 5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay_for(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+
+        let multiplier = 2u32.checked_pow(attempt).unwrap_or(u32::max_value());
+        let backoff = self.base_delay.checked_mul(multiplier).unwrap_or(self.max_delay).min(self.max_delay);
+        let jitter_ms = rand::thread_rng().gen_range(0, self.jitter.as_millis() as u64 + 1);
+        backoff + Duration::from_millis(jitter_ms)
+    }
+}
+
+// Resolves a (possibly relative) Location header against the URI that produced it.
+fn resolve_redirect_uri(current: &hyper::Uri, location: &str) -> Result<hyper::Uri, Error<serde_json::Value>> {
+    if let Ok(absolute) = location.parse::<hyper::Uri>() {
+        if absolute.scheme_part().is_some() {
+            return Ok(absolute);
+        }
+    }
+
+    let mut resolved = String::new();
+    if let Some(scheme) = current.scheme_part() {
+        resolved.push_str(scheme.as_str());
+        resolved.push_str("://");
+    }
+    if let Some(authority) = current.authority_part() {
+        resolved.push_str(authority.as_str());
+    }
+    if !location.starts_with('/') {
+        let current_path = current.path();
+        match current_path.rfind('/') {
+            Some(i) => resolved.push_str(&current_path[..i + 1]),
+            None => resolved.push('/'),
+        }
+    }
+    resolved.push_str(location);
+    resolved.parse().map_err(Error::UriError)
+}
+
+// The bits of a response head dispatch_with_retry needs, kept as a plain struct rather than
+// threading hyper's own Parts type through the retry loop's futures.
+struct ResponseHead {
+    status: hyper::StatusCode,
+    headers: hyper::HeaderMap,
+}
+
+// The mutable state threaded through dispatch_with_retry's retry/redirect loop. method, body and
+// raw_headers live here (rather than being fixed per call) because a redirect can downgrade the
+// method to GET and drop credentials, which then has to stick for the rest of the loop.
+#[derive(Clone)]
+struct DispatchState {
+    uri: hyper::Uri,
+    method: hyper::Method,
+    header_pairs: Vec<(Option<hyper::header::HeaderName>, hyper::header::HeaderValue)>,
+    raw_headers: HashMap<String, String>,
+    body: RequestBody,
+    attempt: u32,
+    redirects: u32,
+}
+
+// Sends a request, retrying on a retryable status code or transport error with exponential
+// backoff (honoring Retry-After when present), and following 3xx redirects by re-dispatching to
+// the Location URI -- downgrading to a bodyless GET for 303, and for 301/302 if the original
+// method was POST, and dropping Authorization and raw_headers (which carry things like api key
+// headers) on a cross-host hop. Because a hyper::Request consumes its body when sent, each
+// attempt is rebuilt from the retained request pieces rather than cloning an already-built
+// request.
+fn dispatch_with_retry<'a, C>(
+    conf: &'a configuration::Configuration<C>,
+    method: hyper::Method,
+    user_agent: Option<String>,
+    raw_headers: HashMap<String, String>,
+    body: RequestBody,
+    uri: hyper::Uri,
+    header_pairs: Vec<(Option<hyper::header::HeaderName>, hyper::header::HeaderValue)>,
+) -> Box<Future<Item = (ResponseHead, hyper::Chunk), Error = Error<serde_json::Value>> + 'a + Send>
+where
+    C: hyper::client::connect::Connect + 'static,
+{
+    let initial_state = DispatchState { uri, method, header_pairs, raw_headers, body, attempt: 0, redirects: 0 };
+
+    Box::new(futures::future::loop_fn(initial_state, move |state| {
+        let req = build_hyper_request(&state.uri, &state.method, &user_agent, &state.header_pairs, &state.raw_headers, &state.body, None);
+
+        conf.client.request(req).then(move |result| -> Box<Future<Item = Loop<(ResponseHead, hyper::Chunk), DispatchState>, Error = Error<serde_json::Value>> + Send> {
+            match result {
+                Err(e) => {
+                    if state.attempt + 1 < conf.retry_policy.max_attempts {
+                        let delay = conf.retry_policy.delay_for(state.attempt, None);
+                        let mut next = state.clone();
+                        next.attempt += 1;
+                        Box::new(Delay::new(Instant::now() + delay).then(move |_| Ok::<_, Error<serde_json::Value>>(Loop::Continue(next))))
+                    } else {
+                        Box::new(futures::future::err(Error::from(e)))
+                    }
+                }
+                Ok(resp) => {
+                    let (parts, body_stream) = resp.into_parts();
+                    let head = ResponseHead { status: parts.status, headers: parts.headers };
+                    Box::new(body_stream.concat2().map_err(Error::from).and_then(move |body| -> Box<Future<Item = Loop<(ResponseHead, hyper::Chunk), DispatchState>, Error = Error<serde_json::Value>> + Send> {
+                        if head.status.is_redirection() && state.redirects < conf.retry_policy.max_redirects {
+                            if let Some(location) = head.headers.get(LOCATION).and_then(|v| v.to_str().ok()) {
+                                if let Ok(new_uri) = resolve_redirect_uri(&state.uri, location) {
+                                    let cross_host = new_uri.host() != state.uri.host();
+
+                                    let downgrade_to_get = head.status == hyper::StatusCode::SEE_OTHER
+                                        || ((head.status == hyper::StatusCode::MOVED_PERMANENTLY || head.status == hyper::StatusCode::FOUND)
+                                            && state.method == hyper::Method::POST);
+                                    let new_method = if downgrade_to_get { hyper::Method::GET } else { state.method.clone() };
+                                    let new_body = if downgrade_to_get { RequestBody::Empty } else { state.body.clone() };
+
+                                    let mut header_pairs = state.header_pairs.clone();
+                                    let mut raw_headers = state.raw_headers.clone();
+                                    if cross_host {
+                                        header_pairs.retain(|(name, _)| name.as_ref().map_or(true, |n| n.as_str() != "authorization"));
+                                        raw_headers.clear();
+                                    }
+
+                                    return Box::new(futures::future::ok(Loop::Continue(DispatchState {
+                                        uri: new_uri,
+                                        method: new_method,
+                                        header_pairs,
+                                        raw_headers,
+                                        body: new_body,
+                                        attempt: state.attempt,
+                                        redirects: state.redirects + 1,
+                                    })));
+                                }
+                            }
+                        }
+
+                        if conf.retry_policy.retryable_status_codes.contains(&head.status) && state.attempt + 1 < conf.retry_policy.max_attempts {
+                            let retry_after = head.headers
+                                .get(RETRY_AFTER)
+                                .and_then(|v| v.to_str().ok())
+                                .and_then(|v| v.parse::<u64>().ok())
+                                .map(Duration::from_secs);
+                            let delay = conf.retry_policy.delay_for(state.attempt, retry_after);
+                            let mut next = state.clone();
+                            next.attempt += 1;
+                            return Box::new(Delay::new(Instant::now() + delay).then(move |_| Ok::<_, Error<serde_json::Value>>(Loop::Continue(next))));
+                        }
+
+                        Box::new(futures::future::ok(Loop::Break((head, body))))
+                    }))
+                }
+            }
+        })
+    }))
+}
+
+fn build_hyper_request(
+    uri: &hyper::Uri,
+    method: &hyper::Method,
+    user_agent: &Option<String>,
+    header_pairs: &[(Option<hyper::header::HeaderName>, hyper::header::HeaderValue)],
+    raw_headers: &HashMap<String, String>,
+    body: &RequestBody,
+    bearer_override: Option<&str>,
+) -> hyper::Request<hyper::Body> {
+    let mut builder = hyper::Request::builder();
+    let req_builder = builder.uri(uri.clone()).method(method.clone());
+
+    if let Some(ref user_agent) = user_agent {
+        req_builder.header(USER_AGENT, user_agent.clone());
+    }
+
+    for (name_opt, value) in header_pairs {
+        if let Some(ref name) = name_opt {
+            if bearer_override.is_some() && *name == AUTHORIZATION {
+                continue;
+            }
+            req_builder.header(name, value.clone());
+        }
+    }
+
+    for (name, value) in raw_headers {
+        req_builder.header(name.as_str(), value.as_str());
+    }
+
+    // A token fetched in response to a 401 challenge always wins over a statically configured
+    // bearer header.
+    if let Some(bearer) = bearer_override {
+        req_builder.header(AUTHORIZATION, format!("Bearer {}", bearer));
+    }
+
+    match body {
+        RequestBody::Form(enc) => req_builder
+            .header(hyper::header::CONTENT_TYPE, MIME_APPLICATION_WWW_FORM_URLENCODED)
+            .body(hyper::Body::from(enc.clone()))
+            .unwrap(),
+        RequestBody::Json(json) => req_builder
+            .header(hyper::header::CONTENT_TYPE, MIME_APPLICATION_JSON)
+            .header(hyper::header::CONTENT_LENGTH, json.len() as u64)
+            .body(hyper::Body::from(json.clone()))
+            .unwrap(),
+        RequestBody::Multipart { boundary, bytes } => req_builder
+            .header(hyper::header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+            .header(hyper::header::CONTENT_LENGTH, bytes.len() as u64)
+            .body(hyper::Body::from(bytes.clone()))
+            .unwrap(),
+        RequestBody::Empty => req_builder
+            .header(hyper::header::CONTENT_LENGTH, 0 as u64)
+            .body(hyper::Body::default())
+            .unwrap(),
+    }
+}
+
+fn scalar_query_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Recursively expands nested objects as deepObject-style bracketed keys (filter[status]=active)
+// and arrays as repeated keys (explode=true) or as one comma-joined value (explode=false).
+fn flatten_query_value(out: &mut Vec<(String, String)>, key: &str, value: &serde_json::Value, explode: bool) {
+    match value {
+        serde_json::Value::Null => {}
+        serde_json::Value::Object(map) => {
+            for (nested_key, nested_value) in map {
+                flatten_query_value(out, &format!("{}[{}]", key, nested_key), nested_value, explode);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            if explode {
+                for item in items {
+                    flatten_query_value(out, key, item, explode);
+                }
+            } else {
+                let joined = items.iter().map(scalar_query_string).collect::<Vec<_>>().join(",");
+                out.push((key.to_owned(), joined));
+            }
+        }
+        other => out.push((key.to_owned(), scalar_query_string(other))),
+    }
+}
+
+// Strips parameters off a Content-Type header value, e.g. "application/json; charset=utf-8" ->
+// "application/json".
+fn content_type_essence(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or(content_type).trim()
+}
+
+fn decode_body<U>(content_type: Option<&str>, no_ret_type: bool, body: &[u8]) -> Result<U, Error<serde_json::Value>>
+where
+    for<'de> U: serde::Deserialize<'de> + 'static,
+{
+    // This is a hack; if there's no_ret_type, U is (), but serde_json gives an error when
+    // deserializing "" into (), so deserialize 'null' into it instead.
+    // An alternate option would be to require U: Default, and then return U::default() here
+    // instead since () implements that, but then we'd need to impl default for all models.
+    if no_ret_type {
+        return serde_json::from_str("null").map_err(Error::from);
+    }
+
+    match content_type.map(content_type_essence) {
+        None | Some(MIME_APPLICATION_JSON) => serde_json::from_slice(body).map_err(Error::from),
+        Some(MIME_APPLICATION_WWW_FORM_URLENCODED) => {
+            ::serde_urlencoded::from_bytes(body)
+                .map_err(|e: serde::de::value::Error| Error::from(serde_json::Error::custom(e.to_string())))
+        }
+        Some(MIME_TEXT_PLAIN) => {
+            let text = ::std::str::from_utf8(body).map_err(Error::Utf8)?;
+            U::deserialize(text.into_deserializer())
+                .map_err(|e: serde::de::value::Error| Error::from(serde_json::Error::custom(e.to_string())))
+        }
+        // Only U = String round-trips here: Vec<u8>'s Deserialize impl expects a sequence, not
+        // bytes, so BytesDeserializer can't feed it -- reject other types explicitly rather than
+        // have that surface as a confusing serde error, and point callers at execute_stream.
+        Some(MIME_APPLICATION_OCTET_STREAM) => {
+            if ::std::any::TypeId::of::<U>() != ::std::any::TypeId::of::<String>() {
+                return Err(Error::UnsupportedMediaType(format!(
+                    "{} only decodes into String; use execute_stream() for binary payloads",
+                    MIME_APPLICATION_OCTET_STREAM,
+                )));
+            }
+            U::deserialize(body.into_deserializer())
+                .map_err(|e: serde::de::value::Error| Error::from(serde_json::Error::custom(e.to_string())))
+        }
+        Some(other) => Err(Error::UnsupportedMediaType(other.to_owned())),
+    }
+}
+
+// Kept separate from an already-built hyper::Request so execute()'s oauth-challenge retry can
+// rebuild the same request a second time with a freshly fetched bearer token attached.
+struct PreparedRequest {
+    uri: hyper::Uri,
+    method: hyper::Method,
+    user_agent: Option<String>,
+    header_pairs: Vec<(Option<hyper::header::HeaderName>, hyper::header::HeaderValue)>,
+    raw_headers: HashMap<String, String>,
+    body: RequestBody,
+    is_oauth: bool,
+    // Set when the cached oauth token is present but within its expiry skew, so execute() can
+    // refresh it before sending rather than going out unauthenticated and waiting for a 401.
+    stale_oauth_token: Option<TokenAuth>,
+}
+
 pub(crate) struct Request {
     auth: Auth,
     method: hyper::Method,
     path: String,
     query_params: HashMap<String, String>,
+    query_struct_params: Vec<(String, String)>,
     no_return_type: bool,
+    returns_stream: bool,
+    // The content types this operation can produce, per the spec; sent as the `Accept` header so
+    // the server (and `decode_body`) can agree on a representation instead of assuming JSON.
+    produces: Vec<String>,
     path_params: HashMap<String, String>,
     form_params: HashMap<String, String>,
+    multipart_params: HashMap<String, FilePart>,
     header_params: HashMap<String, String>,
     // TODO: multiple body params are possible technically, but not supported here.
     serialized_body: Option<String>,
@@ -56,11 +615,15 @@ impl Request {
             method: method,
             path: path,
             query_params: HashMap::new(),
+            query_struct_params: Vec::new(),
             path_params: HashMap::new(),
             form_params: HashMap::new(),
+            multipart_params: HashMap::new(),
             header_params: HashMap::new(),
             serialized_body: None,
             no_return_type: false,
+            returns_stream: false,
+            produces: Vec::new(),
         }
     }
 
@@ -79,6 +642,22 @@ impl Request {
         self
     }
 
+    // Like with_query_param, but expands a whole struct/map of query params per the OpenAPI
+    // deepObject/explode conventions instead of requiring the caller to pre-stringify them.
+    // explode matches the spec's explode keyword: true repeats the key per array element, false
+    // comma-joins them onto a single key.
+    pub fn with_query_struct<T: serde::Serialize>(mut self, param: T, explode: bool) -> Self {
+        match serde_json::to_value(&param).expect("failed to serialize query struct param") {
+            serde_json::Value::Object(map) => {
+                for (key, value) in &map {
+                    flatten_query_value(&mut self.query_struct_params, key, value, explode);
+                }
+            }
+            other => panic!("with_query_struct requires an object/struct, got {}", other),
+        }
+        self
+    }
+
     pub fn with_path_param(mut self, basename: String, param: String) -> Self {
         self.path_params.insert(basename, param);
         self
@@ -89,24 +668,42 @@ impl Request {
         self
     }
 
+    // Takes priority over with_form_param/with_body_param: any file params at all means the whole
+    // body is assembled as multipart, with the form params folded in as text parts.
+    pub fn with_file_param(mut self, basename: String, filename: String, content_type: String, bytes: Vec<u8>) -> Self {
+        self.multipart_params.insert(basename, FilePart { filename, content_type, bytes });
+        self
+    }
+
     pub fn returns_nothing(mut self) -> Self {
         self.no_return_type = true;
         self
     }
 
+    // Use execute_stream rather than execute to run a request built with this.
+    pub fn returns_stream(mut self) -> Self {
+        self.returns_stream = true;
+        self
+    }
+
     pub fn with_auth(mut self, auth: Auth) -> Self {
         self.auth = auth;
         self
     }
 
-    pub fn execute<'a, C, U>(
+    // Content types this operation's spec says it can produce, sent as the Accept header so the
+    // server (and decode_body) can agree on a representation instead of assuming JSON.
+    pub fn with_produces(mut self, content_types: Vec<String>) -> Self {
+        self.produces = content_types;
+        self
+    }
+
+    fn prepare<C>(
         self,
         conf: &configuration::Configuration<C>,
-    ) -> Box<Future<Item = U, Error = Error<serde_json::Value>> + 'a + Send>
+    ) -> Result<PreparedRequest, Error<serde_json::Value>>
     where
         C: hyper::client::connect::Connect + 'static,
-        U: Sized + 'a,
-        for<'de> U: serde::Deserialize<'de> + Send,
     {
         let mut query_string = ::url::form_urlencoded::Serializer::new("".to_owned());
         // raw_headers is for headers we don't know the proper type of (e.g. custom api key
@@ -128,6 +725,12 @@ impl Request {
             query_string.append_pair(&key, &val);
         }
 
+        for (key, val) in &self.query_struct_params {
+            query_string.append_pair(key, val);
+        }
+
+        let mut is_oauth = false;
+        let mut stale_oauth_token = None;
         match self.auth {
             Auth::ApiKey(apikey) => {
                 if let Some(ref key) = conf.api_key {
@@ -156,15 +759,35 @@ impl Request {
                 }
             }
             Auth::Oauth => {
-                if let Some(ref token) = conf.oauth_access_token {
-                    let raw_header_value = format!("Bearer {}", token);
-                    let bearer_auth = hyper::header::HeaderValue::from_str(&raw_header_value).unwrap();
-                    headers.insert(hyper::header::AUTHORIZATION, bearer_auth);
+                is_oauth = true;
+                // Reuse a still-valid cached token so the common case needs no extra round trip;
+                // a token within its expiry skew is handed to execute() to refresh proactively
+                // instead of going out unauthenticated and waiting for a 401.
+                if let Some(ref cached) = *conf.oauth_token.lock().unwrap() {
+                    if !cached.is_expired() {
+                        let raw_header_value = format!("Bearer {}", cached.token);
+                        let bearer_auth = hyper::header::HeaderValue::from_str(&raw_header_value).unwrap();
+                        headers.insert(AUTHORIZATION, bearer_auth);
+                    } else {
+                        stale_oauth_token = Some(cached.clone());
+                    }
+                }
+                if !headers.contains_key(AUTHORIZATION) {
+                    if let Some(ref token) = conf.oauth_access_token {
+                        let raw_header_value = format!("Bearer {}", token);
+                        let bearer_auth = hyper::header::HeaderValue::from_str(&raw_header_value).unwrap();
+                        headers.insert(AUTHORIZATION, bearer_auth);
+                    }
                 }
             }
             Auth::None => {}
         }
 
+        if !self.produces.is_empty() {
+            let accept = hyper::header::HeaderValue::from_str(&self.produces.join(", ")).unwrap();
+            headers.insert(ACCEPT, accept);
+        }
+
         let mut uri_str = format!("{}{}", conf.base_path, path);
 
         let query_string_str = query_string.finish();
@@ -172,89 +795,170 @@ impl Request {
             uri_str += "?";
             uri_str += &query_string_str;
         }
-        let uri: hyper::Uri = match uri_str.parse() {
-            Err(e) => {
-                return Box::new(futures::future::err(Error::UriError(e)));
+        let uri: hyper::Uri = uri_str.parse().map_err(Error::UriError)?;
+
+        let body = if !self.multipart_params.is_empty() {
+            build_multipart_body(&self.form_params, &self.multipart_params)
+        } else if self.form_params.len() > 0 {
+            let mut enc = ::url::form_urlencoded::Serializer::new("".to_owned());
+            for (k, v) in &self.form_params {
+                enc.append_pair(k, v);
             }
-            Ok(u) => u,
+            RequestBody::Form(enc.finish())
+        } else if let Some(ref b) = self.serialized_body {
+            RequestBody::Json(b.clone())
+        } else {
+            RequestBody::Empty
         };
 
-        let mut builder = hyper::Request::builder();
-        let req_builder = builder
-            .uri(uri)
-            .method(self.method);
+        Ok(PreparedRequest {
+            uri,
+            method: self.method,
+            user_agent: conf.user_agent.clone(),
+            header_pairs: headers.into_iter().collect(),
+            raw_headers,
+            body,
+            is_oauth,
+            stale_oauth_token,
+        })
+    }
 
-        {
-            if let Some(ref user_agent) = conf.user_agent {
-                req_builder.header(USER_AGENT, user_agent.clone());
-            }
+    pub fn execute<'a, C, U>(
+        self,
+        conf: &'a configuration::Configuration<C>,
+    ) -> Box<Future<Item = U, Error = Error<serde_json::Value>> + 'a + Send>
+    where
+        C: hyper::client::connect::Connect + 'static,
+        U: Sized + 'a,
+        for<'de> U: serde::Deserialize<'de> + Send + 'static,
+    {
+        let no_ret_type = self.no_return_type;
+        let prepared = match self.prepare(conf) {
+            Ok(p) => p,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        let PreparedRequest { uri, method, user_agent, header_pairs, raw_headers, body, is_oauth, stale_oauth_token } = prepared;
 
-            for (name_opt, value) in headers {
-                if let Some(ref name) = name_opt {
-                    req_builder.header(name, value.clone());
+        // A token within its expiry skew is refreshed up front (using its refresh_token and the
+        // challenge it was originally fetched for) rather than sent out unauthenticated and left
+        // to the 401/challenge retry below.
+        let initial: Box<Future<Item = (ResponseHead, hyper::Chunk), Error = Error<serde_json::Value>> + 'a + Send> =
+            match stale_oauth_token.and_then(|stale| stale.challenge.clone().map(|challenge| (stale, challenge))) {
+                Some((stale, challenge)) => {
+                    let conf2 = conf;
+                    let method2 = method.clone();
+                    let user_agent2 = user_agent.clone();
+                    let raw_headers2 = raw_headers.clone();
+                    let body2 = body.clone();
+                    let uri2 = uri.clone();
+                    let mut header_pairs2 = header_pairs.clone();
+                    Box::new(
+                        fetch_oauth_token(conf, &challenge, stale.refresh_token.as_ref().map(String::as_str))
+                            .and_then(move |token| {
+                                let bearer_auth = hyper::header::HeaderValue::from_str(&format!("Bearer {}", token.token)).unwrap();
+                                header_pairs2.retain(|(name, _)| name.as_ref().map_or(true, |n| n.as_str() != "authorization"));
+                                header_pairs2.push((Some(AUTHORIZATION), bearer_auth));
+                                *conf2.oauth_token.lock().unwrap() = Some(token);
+                                dispatch_with_retry(conf2, method2, user_agent2, raw_headers2, body2, uri2, header_pairs2)
+                            }),
+                    )
                 }
-            }
+                None => dispatch_with_retry(
+                    conf,
+                    method.clone(),
+                    user_agent.clone(),
+                    raw_headers.clone(),
+                    body.clone(),
+                    uri.clone(),
+                    header_pairs.clone(),
+                ),
+            };
 
-            for (name, value) in raw_headers {
-                req_builder.header(name.as_str(), value.as_str());
-            }
-        }
-        
-        let req = if self.form_params.len() > 0 {
-            let mut enc = ::url::form_urlencoded::Serializer::new("".to_owned());
-            for (k, v) in self.form_params {
-                enc.append_pair(&k, &v);
+        Box::new(initial.and_then(move |(head, response_body)| -> Box<Future<Item = U, Error = Error<serde_json::Value>> + 'a + Send> {
+            let challenge = if is_oauth && head.status == hyper::StatusCode::UNAUTHORIZED {
+                head.headers
+                    .get(WWW_AUTHENTICATE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(parse_bearer_challenge)
+            } else {
+                None
+            };
+
+            if let Some(challenge) = challenge {
+                // Only ever retry once: fetch a fresh token for the challenge, cache it, and
+                // re-dispatch the exact same request with it attached.
+                return Box::new(fetch_oauth_token(conf, &challenge, None).and_then(move |token| {
+                    let bearer = token.token.clone();
+                    *conf.oauth_token.lock().unwrap() = Some(token);
+
+                    let retry_req = build_hyper_request(&uri, &method, &user_agent, &header_pairs, &raw_headers, &body, Some(&bearer));
+                    conf.client
+                        .request(retry_req)
+                        .map_err(Error::from)
+                        .and_then(|resp| {
+                            let (head, body) = resp.into_parts();
+                            let content_type = head.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_owned);
+                            body.concat2()
+                                .map_err(Error::from)
+                                .and_then(move |body| {
+                                    if head.status.is_success() {
+                                        Ok((content_type, body))
+                                    } else {
+                                        Err(Error::from((head.status, &*body)))
+                                    }
+                                })
+                        })
+                        .and_then(move |(content_type, body)| decode_body(content_type.as_ref().map(String::as_str), no_ret_type, &body))
+                }));
             }
 
-            req_builder
-                .header(hyper::header::CONTENT_TYPE, MIME_APPLICATION_WWW_FORM_URLENCODED)
-                .body(hyper::Body::from(enc.finish())).unwrap()
+            let content_type = head.headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_owned);
+            Box::new(futures::future::result(if head.status.is_success() {
+                Ok(response_body)
+            } else {
+                Err(Error::from((head.status, &*response_body)))
+            })
+            .and_then(move |body| decode_body(content_type.as_ref().map(String::as_str), no_ret_type, &body)))
+        }))
+    }
 
-        } else if let Some(body) = self.serialized_body {
-            req_builder
-                .header(hyper::header::CONTENT_TYPE, MIME_APPLICATION_JSON)
-                .header(hyper::header::CONTENT_LENGTH, body.len() as u64)
-                .body(hyper::Body::from(body)).unwrap()
+    // Like execute, but for requests built with returns_stream(): skips buffering/deserializing
+    // the body and hands back raw chunks as they arrive. Still checks the status on the response
+    // head first, so HTTP errors surface immediately instead of as malformed bytes.
+    pub fn execute_stream<'a, C>(
+        self,
+        conf: &'a configuration::Configuration<C>,
+    ) -> Box<Stream<Item = hyper::Chunk, Error = Error<serde_json::Value>> + 'a + Send>
+    where
+        C: hyper::client::connect::Connect + 'static,
+    {
+        debug_assert!(self.returns_stream, "execute_stream called on a request not built with returns_stream()");
 
-        } else {
-            req_builder
-                .header(hyper::header::CONTENT_LENGTH, 0 as u64)
-                .body(hyper::Body::default()).unwrap()
+        let prepared = match self.prepare(conf) {
+            Ok(p) => p,
+            Err(e) => return Box::new(futures::stream::once(Err(e))),
         };
+        let req = build_hyper_request(&prepared.uri, &prepared.method, &prepared.user_agent, &prepared.header_pairs, &prepared.raw_headers, &prepared.body, None);
 
-        let no_ret_type = self.no_return_type;
-        let res = conf.client
+        Box::new(
+            conf.client
                 .request(req)
-                .map_err(|e| Error::from(e))
-                .and_then(|resp| {
+                .map_err(Error::from)
+                .map(|resp| -> Box<Stream<Item = hyper::Chunk, Error = Error<serde_json::Value>> + 'a + Send> {
                     let (head, body) = resp.into_parts();
-                    body.concat2()
-                        .and_then(move |body| Ok((head.status, body)))
-                        .map_err(|e| Error::from(e))
-                })
-                .and_then(|(status, body)| {
-                    if status.is_success() {
-                        Ok(body)
+                    if head.status.is_success() {
+                        Box::new(body.map_err(Error::from))
                     } else {
-                        Err(Error::from((status, &*body)))
+                        Box::new(
+                            body.concat2()
+                                .map_err(Error::from)
+                                .and_then(move |body| Err(Error::from((head.status, &*body))))
+                                .into_stream(),
+                        )
                     }
-                });
-        Box::new(
-            res
-                .and_then(move |body| {
-                    let parsed: Result<U, _> = if no_ret_type {
-                        // This is a hack; if there's no_ret_type, U is (), but serde_json gives an
-                        // error when deserializing "" into (), so deserialize 'null' into it
-                        // instead.
-                        // An alternate option would be to require U: Default, and then return
-                        // U::default() here instead since () implements that, but then we'd
-                        // need to impl default for all models.
-                        serde_json::from_str("null")
-                    } else {
-                        serde_json::from_slice(&body)
-                    };
-                    parsed.map_err(|e| Error::from(e))
                 })
+                .into_stream()
+                .flatten(),
         )
     }
 }